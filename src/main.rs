@@ -1,19 +1,30 @@
-#![feature(proc_macro_hygiene, decl_macro)]
-
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
-use std::{thread, time};
-
+use std::collections::{BTreeMap, HashMap};
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::time::Instant;
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
 use base64;
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg};
 use handlebars::Handlebars;
-use reqwest::{blocking::Client, header};
+use reqwest::{header, Client};
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 
 #[macro_use]
 extern crate rocket;
-use rocket::http::{ContentType, Status};
-use rocket::{response, Config};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::config::{SecretKey, TlsConfig};
+use rocket::form::Form;
+use rocket::http::{ContentType, Cookie, CookieJar, Status};
+use rocket::request::{self, FromRequest};
+use rocket::response::stream::{Event, EventStream};
+use rocket::response::{self, Redirect, Responder};
+use rocket::tokio::sync::{broadcast, RwLock};
+use rocket::tokio::time;
+use rocket::{Request, Response, State};
 
 #[macro_use]
 extern crate rust_embed;
@@ -23,12 +34,33 @@ extern crate serde_json;
 
 const DEFAULT_HOST: &str = "localhost";
 const DEFAULT_PORT: &str = "80";
-const DEFAULT_BIND_HOST: &str = DEFAULT_HOST;
+const DEFAULT_BIND_HOST: &str = "127.0.0.1";
 const DEFAULT_BIND_PORT: &str = "8000";
 const DEFAULT_WEB_UI_USER: &str = "root";
 const API_URL_BASE: &str = "/api/v2.0/";
 const TEMPLATE_INDEX: &str = "index.html";
+const TEMPLATE_LOGIN: &str = "login.html";
 const ICON_FREEBSD: &str = "/static/icons/beastie.png";
+const STATIC_CACHE_CONTROL: &str = "max-age=604800";
+const SSE_KEEPALIVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(20);
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+const POLL_BACKOFF_INITIAL: std::time::Duration = std::time::Duration::from_secs(5);
+const POLL_BACKOFF_MAX: std::time::Duration = std::time::Duration::from_secs(300);
+const SESSION_COOKIE_NAME: &str = "session";
+const SESSION_TTL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+const SSE_CHANNEL_CAPACITY: usize = 16;
+const SESSION_SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 10);
+
+/// Live session tokens mapped to the time they were last used; a lookup
+/// refreshes the timestamp, giving the UI a sliding expiry.
+type Sessions = Arc<RwLock<HashMap<String, Instant>>>;
+
+/// Tracks the health of the background poll loop so the index template can
+/// render a "data may be stale" banner instead of silently serving old data.
+struct PollStatus {
+	last_successful_poll: Option<Instant>,
+	last_error: Option<String>,
+}
 
 #[derive(RustEmbed)]
 #[folder = "static/"]
@@ -38,6 +70,126 @@ struct Static;
 #[folder = "templates/"]
 struct Templates;
 
+/// Injects a baseline set of security headers onto every response, since the
+/// dashboard renders remote icon URLs and admin portal links and otherwise
+/// ships no hardening at all.
+struct AppHeaders();
+
+#[rocket::async_trait]
+impl Fairing for AppHeaders {
+	fn info(&self) -> Info {
+		Info {
+			name: "Security headers",
+			kind: Kind::Response,
+		}
+	}
+
+	async fn on_response<'r>(&self, _request: &'r Request<'_>, response: &mut Response<'r>) {
+		response.set_raw_header("X-Frame-Options", "SAMEORIGIN");
+		response.set_raw_header("X-Content-Type-Options", "nosniff");
+		response.set_raw_header("Referrer-Policy", "same-origin");
+		response.set_raw_header(
+			"Content-Security-Policy",
+			"default-src 'self'; img-src 'self' https://raw.githubusercontent.com data:",
+		);
+	}
+}
+
+/// Request guard that only succeeds for requests carrying a session cookie
+/// naming a live, unexpired entry in the managed `Sessions` map.
+struct AuthenticatedUser;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthenticatedUser {
+	type Error = ();
+
+	async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+		let sessions = request.guard::<&State<Sessions>>().await.unwrap();
+		let token = match request.cookies().get_private(SESSION_COOKIE_NAME) {
+			Some(cookie) => cookie.value().to_string(),
+			None => return request::Outcome::Error((Status::Unauthorized, ())),
+		};
+
+		let mut sessions = sessions.write().await;
+
+		match sessions.get_mut(&token) {
+			Some(last_seen) if last_seen.elapsed() < SESSION_TTL => {
+				*last_seen = Instant::now();
+				request::Outcome::Success(AuthenticatedUser)
+			}
+			_ => {
+				sessions.remove(&token);
+				request::Outcome::Error((Status::Unauthorized, ()))
+			}
+		}
+	}
+}
+
+/// Generates a random 128-bit session token.
+fn generate_session_token() -> String {
+	let mut bytes = [0u8; 16];
+	OsRng.fill_bytes(&mut bytes);
+	base64::encode(bytes)
+}
+
+/// Generates a random 512-bit key for signing the private session cookie.
+/// Rocket's `secrets` feature (required for `CookieJar::add_private`) must be
+/// enabled for this key to take effect. `SecretKey::from` panics on anything
+/// shorter than 64 bytes, so the buffer size here is load-bearing.
+fn generate_secret_key() -> SecretKey {
+	let mut bytes = [0u8; 64];
+	OsRng.fill_bytes(&mut bytes);
+	SecretKey::from(&bytes)
+}
+
+#[catch(401)]
+fn unauthorized() -> Redirect {
+	Redirect::to("/login")
+}
+
+#[derive(FromForm)]
+struct LoginForm {
+	password: String,
+}
+
+#[get("/login")]
+fn login(handlebars: &State<Handlebars>) -> rocket::response::content::RawHtml<String> {
+	rocket::response::content::RawHtml(handlebars.render(TEMPLATE_LOGIN, &json!({})).unwrap())
+}
+
+#[post("/login", data = "<form>")]
+async fn login_submit(
+	form: Form<LoginForm>,
+	password_hash: &State<String>,
+	sessions: &State<Sessions>,
+	cookies: &CookieJar<'_>,
+) -> Result<Redirect, Status> {
+	let parsed_hash =
+		PasswordHash::new(password_hash).map_err(|_| Status::InternalServerError)?;
+
+	Argon2::default()
+		.verify_password(form.password.as_bytes(), &parsed_hash)
+		.map_err(|_| Status::Unauthorized)?;
+
+	let token = generate_session_token();
+
+	sessions.write().await.insert(token.clone(), Instant::now());
+	cookies.add_private(Cookie::new(SESSION_COOKIE_NAME, token));
+
+	Ok(Redirect::to("/"))
+}
+
+/// Hashes `password` into an Argon2 PHC string suitable for
+/// `--ui-password-hash`.
+fn hash_password(password: &str) -> String {
+	let salt = SaltString::generate(&mut OsRng);
+
+	Argon2::default()
+		.hash_password(password.as_bytes(), &salt)
+		.unwrap()
+		.to_string()
+}
+
 #[derive(Serialize)]
 struct Jail {
 	jail_ip: String,
@@ -45,14 +197,10 @@ struct Jail {
 	icon_url: Option<String>,
 }
 
-fn list(client: &Client, api_url_base: &String) -> std::collections::HashMap<String, Jail> {
+async fn list(client: &Client, api_url_base: &str) -> anyhow::Result<HashMap<String, Jail>> {
 	let mut jails = HashMap::new();
-	let response = client
-		.get(&(api_url_base.to_owned() + "jail"))
-		.send()
-		.unwrap();
-
-	let obj = json::parse(&response.text().unwrap()).unwrap();
+	let response = client.get(&(api_url_base.to_owned() + "jail")).send().await?;
+	let obj = json::parse(&response.text().await?)?;
 
 	for jail_obj in obj.members() {
 		jails.insert(
@@ -65,31 +213,29 @@ fn list(client: &Client, api_url_base: &String) -> std::collections::HashMap<Str
 		);
 	}
 
-	let response = client
-		.get(&(api_url_base.to_owned() + "plugin"))
-		.send()
-		.unwrap();
-
-	let obj = json::parse(&response.text().unwrap()).unwrap();
+	let response = client.get(&(api_url_base.to_owned() + "plugin")).send().await?;
+	let obj = json::parse(&response.text().await?)?;
 
 	for plugin_obj in obj.members() {
 		let name = plugin_obj["name"].to_string();
 
 		match &plugin_obj["admin_portals"] {
 			json::JsonValue::Array(admin_urls) => {
-				jails.entry(name.clone()).and_modify(|jail| {
-					jail.admin_url = Some(admin_urls[0].to_string());
-					jail.icon_url = Some(
-						plugin_obj["plugin_repository"]
-							.to_string()
-							.trim_end_matches(".git")
-							.replace("github.com", "raw.githubusercontent.com")
-							+ &format!(
-								"/master/icons/{}.png",
-								name.replace("plexmediaserver", "plex")
-							),
-					);
-				});
+				if let Some(admin_url) = admin_urls.first() {
+					jails.entry(name.clone()).and_modify(|jail| {
+						jail.admin_url = Some(admin_url.to_string());
+						jail.icon_url = Some(
+							plugin_obj["plugin_repository"]
+								.to_string()
+								.trim_end_matches(".git")
+								.replace("github.com", "raw.githubusercontent.com")
+								+ &format!(
+									"/master/icons/{}.png",
+									name.replace("plexmediaserver", "plex")
+								),
+						);
+					});
+				}
 			}
 			_ => (),
 		}
@@ -101,22 +247,85 @@ fn list(client: &Client, api_url_base: &String) -> std::collections::HashMap<Str
 		}
 	}
 
-	jails
+	Ok(jails)
 }
 
-#[get("/")]
-fn index(
-	handlebars: rocket::State<Handlebars>,
-	arc_jails: rocket::State<Arc<RwLock<HashMap<String, Jail>>>>,
-) -> rocket::response::content::Html<String> {
-	let jails = arc_jails.read().unwrap();
+/// Resolves `host` to a bind address: a literal IP parses directly, anything
+/// else (e.g. a hostname) is looked up via DNS. Exits with a clear error
+/// rather than panicking if neither works.
+fn resolve_bind_host(host: &str) -> std::net::IpAddr {
+	if let Ok(ip) = host.parse() {
+		return ip;
+	}
+
+	(host, 0)
+		.to_socket_addrs()
+		.ok()
+		.and_then(|mut addrs| addrs.next())
+		.map(|addr| addr.ip())
+		.unwrap_or_else(|| {
+			eprintln!("error: could not resolve bind host '{}'", host);
+			std::process::exit(1);
+		})
+}
 
-	rocket::response::content::Html(handlebars.render(TEMPLATE_INDEX, &json!(&*jails)).unwrap())
+/// Computes a strong ETag (the hex SHA-256) for every embedded static asset,
+/// once up front, so `static_file` can answer conditional requests without
+/// re-hashing on every call.
+fn build_etags() -> HashMap<String, String> {
+	let mut etags = HashMap::new();
+
+	for filename in Static::iter() {
+		if let Some(data) = Static::get(&filename) {
+			etags.insert(filename.to_string(), format!("\"{:x}\"", Sha256::digest(&data)));
+		}
+	}
+
+	etags
+}
+
+#[get("/")]
+async fn index(
+	_user: AuthenticatedUser,
+	handlebars: &State<Handlebars>,
+	arc_jails: &State<Arc<RwLock<HashMap<String, Jail>>>>,
+	poll_status: &State<Arc<RwLock<PollStatus>>>,
+) -> rocket::response::content::RawHtml<String> {
+	let jails = arc_jails.read().await;
+	let poll_status = poll_status.read().await;
+	let stale_seconds = poll_status
+		.last_successful_poll
+		.map(|instant| instant.elapsed().as_secs())
+		.filter(|&seconds| seconds > POLL_INTERVAL.as_secs());
+
+	rocket::response::content::RawHtml(
+		handlebars
+			.render(
+				TEMPLATE_INDEX,
+				&json!({
+					"jails": &*jails,
+					"stale_seconds": stale_seconds,
+					"last_error": &poll_status.last_error,
+				}),
+			)
+			.unwrap(),
+	)
 }
 
 #[get("/static/<path..>")]
-fn static_file<'r>(path: std::path::PathBuf) -> response::Result<'r> {
+async fn static_file<'r>(
+	path: std::path::PathBuf,
+	etags: &State<HashMap<String, String>>,
+	request: &'r Request<'_>,
+) -> response::Result<'r> {
 	let filename = path.display().to_string();
+	let etag = etags.get(&filename).cloned();
+
+	if let Some(etag) = &etag {
+		if request.headers().get_one("If-None-Match") == Some(etag.as_str()) {
+			return response::Response::build().status(Status::NotModified).ok();
+		}
+	}
 
 	Static::get(&filename).map_or_else(
 		|| Err(Status::NotFound),
@@ -125,22 +334,60 @@ fn static_file<'r>(path: std::path::PathBuf) -> response::Result<'r> {
 				.as_path()
 				.extension()
 				.and_then(std::ffi::OsStr::to_str)
-				.ok_or_else(|| Status::new(400, "Could not get file extension"))?;
-			let content_type = ContentType::from_extension(ext)
-				.ok_or_else(|| Status::new(400, "Could not get file content type"))?;
-			response::Response::build()
-				.header(content_type)
-				.sized_body(std::io::Cursor::new(d))
-				.ok()
+				.ok_or(Status::BadRequest)?;
+			let content_type = ContentType::from_extension(ext).ok_or(Status::BadRequest)?;
+			let mut response = d.into_owned().respond_to(request)?;
+
+			response.set_header(content_type);
+			response.set_raw_header("Cache-Control", STATIC_CACHE_CONTROL);
+
+			if let Some(etag) = etag {
+				response.set_raw_header("ETag", etag);
+			}
+
+			Ok(response)
 		},
 	)
 }
 
-fn main() {
+#[get("/events")]
+fn events(
+	_user: AuthenticatedUser,
+	subscribers: &State<broadcast::Sender<String>>,
+) -> EventStream![Event] {
+	let mut receiver = subscribers.subscribe();
+
+	EventStream! {
+		loop {
+			match time::timeout(SSE_KEEPALIVE_INTERVAL, receiver.recv()).await {
+				Ok(Ok(snapshot)) => yield Event::data(snapshot),
+				Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+				Ok(Err(broadcast::error::RecvError::Closed)) => break,
+				Err(_) => yield Event::comment("keep-alive"),
+			}
+		}
+	}
+}
+
+#[launch]
+fn rocket() -> _ {
 	let matches = App::new(env!("CARGO_PKG_NAME"))
 		.version(env!("CARGO_PKG_VERSION"))
 		.about(env!("CARGO_PKG_DESCRIPTION"))
 		.author(env!("CARGO_PKG_AUTHORS"))
+		.setting(AppSettings::SubcommandsNegateReqs)
+		.subcommand(
+			App::new("hash-password")
+				.about("Generate an Argon2 PHC hash for use with --ui-password-hash")
+				.arg(Arg::with_name("password").required(true).help("Password to hash")),
+		)
+		.arg(
+			Arg::with_name("ui-password-hash")
+				.long("ui-password-hash")
+				.help("Argon2 PHC hash guarding the web UI (see the hash-password subcommand)")
+				.takes_value(true)
+				.required(true),
+		)
 		.arg(
 			Arg::with_name("host")
 				.help("FreeNAS/TrueNAS host")
@@ -186,14 +433,71 @@ fn main() {
 				.long("secure")
 				.help("Connect using HTTPS"),
 		)
+		.arg(
+			Arg::with_name("secret-key")
+				.long("secret-key")
+				.help(
+					"Base64-encoded 512-bit (64-byte) key used to sign session cookies; \
+					 keeps sessions alive across restarts. A random ephemeral key is \
+					 generated (and sessions invalidated on every restart) if omitted",
+				)
+				.takes_value(true),
+		)
+		.arg(
+			Arg::with_name("tls-cert")
+				.long("tls-cert")
+				.help("PEM-encoded TLS certificate to serve the dashboard over HTTPS")
+				.takes_value(true)
+				.requires("tls-key"),
+		)
+		.arg(
+			Arg::with_name("tls-key")
+				.long("tls-key")
+				.help("PEM-encoded TLS private key to serve the dashboard over HTTPS")
+				.takes_value(true)
+				.requires("tls-cert"),
+		)
 		.get_matches();
 
-	let host = matches.value_of("host").unwrap();
-	let port = matches.value_of("port").unwrap();
-	let bind_host = matches.value_of("bind-host").unwrap();
-	let bind_port = matches.value_of("bind-port").unwrap();
-	let user = matches.value_of("user").unwrap();
-	let password = matches.value_of("password").unwrap();
+	if let Some(matches) = matches.subcommand_matches("hash-password") {
+		println!("{}", hash_password(matches.value_of("password").unwrap()));
+		std::process::exit(0);
+	}
+
+	let host = matches.value_of("host").unwrap().to_string();
+	let port = matches.value_of("port").unwrap().to_string();
+	let bind_host = matches.value_of("bind-host").unwrap().to_string();
+	let bind_port: u16 = matches.value_of("bind-port").unwrap().parse().unwrap();
+	let user = matches.value_of("user").unwrap().to_string();
+	let password = matches.value_of("password").unwrap().to_string();
+	let ui_password_hash = matches.value_of("ui-password-hash").unwrap().to_string();
+	let tls = match (matches.value_of("tls-cert"), matches.value_of("tls-key")) {
+		(Some(cert), Some(key)) => Some(TlsConfig::from_paths(cert, key)),
+		_ => None,
+	};
+	let secret_key = match matches.value_of("secret-key") {
+		Some(key) => {
+			let bytes = base64::decode(key).expect("--secret-key must be valid base64");
+
+			if bytes.len() != 64 {
+				eprintln!(
+					"error: --secret-key must decode to 64 bytes (512 bits), got {}",
+					bytes.len()
+				);
+				std::process::exit(1);
+			}
+
+			SecretKey::from(&bytes)
+		}
+		None => {
+			eprintln!(
+				"warning: no --secret-key given; generating an ephemeral one, \
+				 so all sessions will be invalidated on the next restart"
+			);
+
+			generate_secret_key()
+		}
+	};
 
 	let protocol = match matches.is_present("secure") {
 		true => "https",
@@ -202,11 +506,30 @@ fn main() {
 
 	let auth_value = format!("Basic {}", base64::encode(format!("{}:{}", user, password)));
 	let api_url_base = format!("{}://{}:{}{}", protocol, host, port, API_URL_BASE);
-	let jails: HashMap<String, Jail> = HashMap::new();
-	let arc_jails = Arc::new(RwLock::new(jails));
+	let arc_jails = Arc::new(RwLock::new(HashMap::<String, Jail>::new()));
 	let arc2_jails = arc_jails.clone();
+	let (subscribers, _) = broadcast::channel(SSE_CHANNEL_CAPACITY);
+	let subscribers2 = subscribers.clone();
+	let poll_status = Arc::new(RwLock::new(PollStatus {
+		last_successful_poll: None,
+		last_error: None,
+	}));
+	let poll_status2 = poll_status.clone();
+	let sessions: Sessions = Arc::new(RwLock::new(HashMap::new()));
+	let sessions2 = sessions.clone();
+
+	rocket::tokio::spawn(async move {
+		loop {
+			time::sleep(SESSION_SWEEP_INTERVAL).await;
+
+			sessions2
+				.write()
+				.await
+				.retain(|_, last_seen| last_seen.elapsed() < SESSION_TTL);
+		}
+	});
 
-	thread::spawn(move || {
+	rocket::tokio::spawn(async move {
 		let mut headers = header::HeaderMap::new();
 		headers.insert(
 			header::AUTHORIZATION,
@@ -214,34 +537,69 @@ fn main() {
 		);
 
 		let client = Client::builder().default_headers(headers).build().unwrap();
+		let mut previous_snapshot = String::new();
+		let mut backoff = POLL_BACKOFF_INITIAL;
 
 		loop {
-			let mut jails = arc2_jails.write().unwrap();
-			*jails = list(&client, &api_url_base);
-
-			std::mem::drop(jails);
-
-			thread::sleep(time::Duration::from_secs(30));
+			match list(&client, &api_url_base).await {
+				Ok(new_jails) => {
+					// HashMap iteration order is randomized per-instance, so serialize
+					// through a BTreeMap first to get a stable ordering to diff against.
+					let ordered: BTreeMap<&String, &Jail> = new_jails.iter().collect();
+					let snapshot = json!(&ordered).to_string();
+
+					if snapshot != previous_snapshot {
+						let _ = subscribers2.send(snapshot.clone());
+						previous_snapshot = snapshot;
+					}
+
+					*arc2_jails.write().await = new_jails;
+
+					let mut poll_status = poll_status2.write().await;
+					poll_status.last_successful_poll = Some(Instant::now());
+					poll_status.last_error = None;
+
+					backoff = POLL_BACKOFF_INITIAL;
+					time::sleep(POLL_INTERVAL).await;
+				}
+				Err(err) => {
+					eprintln!("error polling TrueNAS API: {:#}", err);
+
+					poll_status2.write().await.last_error = Some(err.to_string());
+
+					time::sleep(backoff).await;
+					backoff = std::cmp::min(backoff * 2, POLL_BACKOFF_MAX);
+				}
+			}
 		}
 	});
 
-	let env = rocket::config::Environment::active().unwrap();
 	let mut handlebars = Handlebars::new();
-	let template = Templates::get(TEMPLATE_INDEX).unwrap();
 
-	handlebars
-		.register_template_string(TEMPLATE_INDEX, std::str::from_utf8(&template).unwrap())
-		.unwrap();
+	for name in &[TEMPLATE_INDEX, TEMPLATE_LOGIN] {
+		let template = Templates::get(name).unwrap();
 
-	rocket::custom(
-		Config::build(env)
-			.address(bind_host)
-			.port(bind_port.parse().unwrap())
-			.finalize()
-			.unwrap(),
-	)
-	.manage(handlebars)
-	.manage(arc_jails)
-	.mount("/", routes![static_file, index])
-	.launch();
+		handlebars
+			.register_template_string(*name, std::str::from_utf8(&template).unwrap())
+			.unwrap();
+	}
+
+	rocket::build()
+		.configure(rocket::Config {
+			address: resolve_bind_host(&bind_host),
+			port: bind_port,
+			tls,
+			secret_key,
+			..rocket::Config::default()
+		})
+		.attach(AppHeaders())
+		.manage(handlebars)
+		.manage(arc_jails)
+		.manage(build_etags())
+		.manage(subscribers)
+		.manage(poll_status)
+		.manage(sessions)
+		.manage(ui_password_hash)
+		.mount("/", routes![static_file, index, events, login, login_submit])
+		.register("/", catchers![unauthorized])
 }